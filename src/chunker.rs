@@ -0,0 +1,176 @@
+//! Content-defined chunking (CDC).
+//!
+//! Whole-file hashing only finds byte-identical files; large files that
+//! share most of their content (VM images, logs with appended data) are
+//! missed entirely. This module slides a buzhash rolling hash over a file
+//! and cuts a chunk boundary whenever the hash matches a fixed mask, then
+//! hashes each chunk independently so files can be compared at the
+//! chunk level instead of all-or-nothing.
+
+use crate::hash::Algorithm;
+use std::io::{BufReader, Read};
+use std::path::Path;
+
+/// Rolling hash window, in bytes.
+const WINDOW: usize = 64;
+/// Boundary mask: a cut happens whenever `rolling_hash & MASK == MASK`,
+/// which (for a well-mixed hash) gives ~8 KiB average chunks.
+const MASK: u32 = (1 << 13) - 1;
+/// Never cut before this many bytes, so a boundary can't immediately
+/// re-trigger on the next byte.
+const MIN_CHUNK_SIZE: usize = 2 * 1024;
+/// Force a cut if no boundary has been found within this many bytes.
+const MAX_CHUNK_SIZE: usize = 64 * 1024;
+
+/// One content-defined chunk of a file.
+pub struct Chunk {
+    pub index: usize,
+    pub hash: String,
+    /// Name of the algorithm that produced `hash`, e.g. "blake3". Stored
+    /// alongside the hash so chunks from different algorithm runs can't be
+    /// compared as if they shared a hash space.
+    pub hash_algo: String,
+    pub length: u64,
+}
+
+/// Scatter table mapping byte values to pseudo-random 32-bit words, used by
+/// buzhash to fold bytes in and out of the rolling window.
+fn buzhash_table() -> [u32; 256] {
+    let mut table = [0u32; 256];
+    let mut seed: u32 = 0x9E3779B9;
+    for entry in table.iter_mut() {
+        seed ^= seed << 13;
+        seed ^= seed >> 17;
+        seed ^= seed << 5;
+        *entry = seed;
+    }
+    table
+}
+
+/// Open `path` and split its contents into content-defined chunks.
+///
+/// Chunking reads every byte of the file regardless of whether its size
+/// collides with another file's, unlike the whole-file hash in `hash.rs`:
+/// partial overlap (the whole point of this module) can happen between
+/// files of different sizes, so the size-based skip that works for
+/// whole-file dedup doesn't apply here.
+pub fn chunk_file_at_path(path: &Path, algorithm: Algorithm) -> std::io::Result<Vec<Chunk>> {
+    chunk_reader(BufReader::new(std::fs::File::open(path)?), algorithm)
+}
+
+/// Split a reader's contents into content-defined chunks, hashing each one
+/// with `algorithm`. `reader` should be buffered: this reads one byte at a
+/// time to slide the window, which would be one syscall per byte otherwise.
+fn chunk_reader<R: Read>(mut reader: R, algorithm: Algorithm) -> std::io::Result<Vec<Chunk>> {
+    let table = buzhash_table();
+    let mut window = [0u8; WINDOW];
+    let mut window_pos = 0usize;
+    let mut filled = 0usize;
+    let mut rolling_hash: u32 = 0;
+
+    let mut chunks = vec![];
+    let mut checksummer = algorithm.checksummer();
+    let mut chunk_len: usize = 0;
+    let mut index = 0usize;
+    let mut byte_buf = [0u8; 1];
+
+    loop {
+        let n = reader.read(&mut byte_buf)?;
+        if n == 0 {
+            if chunk_len > 0 {
+                chunks.push(Chunk {
+                    index,
+                    hash: checksummer.finish(),
+                    hash_algo: algorithm.name().to_string(),
+                    length: chunk_len as u64,
+                });
+            }
+            break;
+        }
+        let byte = byte_buf[0];
+        checksummer.update(&byte_buf);
+        chunk_len += 1;
+
+        if filled < WINDOW {
+            window[window_pos] = byte;
+            rolling_hash = rolling_hash.rotate_left(1) ^ table[byte as usize];
+            filled += 1;
+        } else {
+            let departing = window[window_pos];
+            window[window_pos] = byte;
+            rolling_hash = rolling_hash.rotate_left(1)
+                ^ table[departing as usize].rotate_left(WINDOW as u32)
+                ^ table[byte as usize];
+        }
+        window_pos = (window_pos + 1) % WINDOW;
+
+        let at_boundary =
+            filled >= WINDOW && chunk_len >= MIN_CHUNK_SIZE && (rolling_hash & MASK) == MASK;
+        let forced = chunk_len >= MAX_CHUNK_SIZE;
+
+        if at_boundary || forced {
+            chunks.push(Chunk {
+                index,
+                hash: checksummer.finish(),
+                hash_algo: algorithm.name().to_string(),
+                length: chunk_len as u64,
+            });
+            index += 1;
+            chunk_len = 0;
+            checksummer = algorithm.checksummer();
+        }
+    }
+
+    Ok(chunks)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hash::Algorithm;
+    use std::io::Cursor;
+
+    #[test]
+    fn chunks_cover_the_whole_input_with_no_gaps_or_overlap() {
+        let data = vec![0x42u8; MAX_CHUNK_SIZE * 3 + 17];
+        let chunks = chunk_reader(Cursor::new(data.clone()), Algorithm::Blake3).unwrap();
+        let total: u64 = chunks.iter().map(|chunk| chunk.length).sum();
+        assert_eq!(total, data.len() as u64);
+    }
+
+    #[test]
+    fn a_boundary_is_forced_when_no_cut_point_is_found() {
+        // Uniform bytes keep the rolling hash constant, so it may never
+        // satisfy the boundary mask; MAX_CHUNK_SIZE must still force a cut.
+        let data = vec![0u8; MAX_CHUNK_SIZE * 2];
+        let chunks = chunk_reader(Cursor::new(data), Algorithm::Blake3).unwrap();
+        assert!(chunks.len() >= 2);
+        assert!(chunks.iter().all(|chunk| chunk.length <= MAX_CHUNK_SIZE as u64));
+    }
+
+    #[test]
+    fn no_chunk_is_smaller_than_the_minimum_except_the_last() {
+        let data = vec![0u8; MAX_CHUNK_SIZE * 4];
+        let chunks = chunk_reader(Cursor::new(data), Algorithm::Blake3).unwrap();
+        for chunk in &chunks[..chunks.len() - 1] {
+            assert!(chunk.length >= MIN_CHUNK_SIZE as u64);
+        }
+    }
+
+    #[test]
+    fn identical_inputs_produce_identical_chunk_hashes() {
+        let data = b"the quick brown fox jumps over the lazy dog ".repeat(2000);
+        let a = chunk_reader(Cursor::new(data.clone()), Algorithm::Blake3).unwrap();
+        let b = chunk_reader(Cursor::new(data), Algorithm::Blake3).unwrap();
+        let hashes_a: Vec<&str> = a.iter().map(|chunk| chunk.hash.as_str()).collect();
+        let hashes_b: Vec<&str> = b.iter().map(|chunk| chunk.hash.as_str()).collect();
+        assert_eq!(hashes_a, hashes_b);
+    }
+
+    #[test]
+    fn every_chunk_is_tagged_with_the_requested_algorithm() {
+        let data = vec![0x7eu8; MAX_CHUNK_SIZE * 2];
+        let chunks = chunk_reader(Cursor::new(data), Algorithm::Sha256).unwrap();
+        assert!(chunks.iter().all(|chunk| chunk.hash_algo == "sha256"));
+    }
+}
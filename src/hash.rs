@@ -0,0 +1,83 @@
+//! Pluggable checksum algorithms.
+//!
+//! `calculate_digest` used to hardcode MD5, which is collision-prone enough
+//! that two distinct files could register as false duplicates. This module
+//! hides each algorithm behind a small `Checksummer` trait so the CLI can
+//! pick one at runtime, and the chosen algorithm's name is stored alongside
+//! every digest so a database can record which function produced it.
+
+use clap::ValueEnum;
+use md5::{Digest, Md5};
+use sha2::Sha256;
+
+/// Which checksum algorithm produced a digest.
+#[derive(Clone, Copy, Debug, ValueEnum)]
+pub enum Algorithm {
+    Md5,
+    Sha256,
+    Blake3,
+}
+
+impl Algorithm {
+    /// The name stored in the `hash_algo` column.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Algorithm::Md5 => "md5",
+            Algorithm::Sha256 => "sha256",
+            Algorithm::Blake3 => "blake3",
+        }
+    }
+
+    /// Build a fresh streaming checksummer for this algorithm.
+    pub fn checksummer(&self) -> Box<dyn Checksummer> {
+        match self {
+            Algorithm::Md5 => Box::new(Md5::new()),
+            Algorithm::Sha256 => Box::new(Sha256::new()),
+            Algorithm::Blake3 => Box::new(blake3::Hasher::new()),
+        }
+    }
+}
+
+impl Default for Algorithm {
+    /// BLAKE3 is the default: faster than MD5/SHA-256 and collision-resistant.
+    fn default() -> Self {
+        Algorithm::Blake3
+    }
+}
+
+/// A streaming digest that can be fed file contents in chunks and finalized
+/// into a hex string.
+pub trait Checksummer {
+    fn update(&mut self, chunk: &[u8]);
+    fn finish(self: Box<Self>) -> String;
+}
+
+impl Checksummer for Md5 {
+    fn update(&mut self, chunk: &[u8]) {
+        Digest::update(self, chunk);
+    }
+
+    fn finish(self: Box<Self>) -> String {
+        format!("{:x}", Digest::finalize(*self))
+    }
+}
+
+impl Checksummer for Sha256 {
+    fn update(&mut self, chunk: &[u8]) {
+        Digest::update(self, chunk);
+    }
+
+    fn finish(self: Box<Self>) -> String {
+        format!("{:x}", Digest::finalize(*self))
+    }
+}
+
+impl Checksummer for blake3::Hasher {
+    fn update(&mut self, chunk: &[u8]) {
+        blake3::Hasher::update(self, chunk);
+    }
+
+    fn finish(self: Box<Self>) -> String {
+        blake3::Hasher::finalize(&self).to_hex().to_string()
+    }
+}
@@ -1,13 +1,26 @@
+mod chunker;
+mod hash;
+mod store;
+
+use crate::chunker::Chunk;
+use crate::hash::Algorithm;
+use crate::store::{self, Backend, JsonlStore, RecordStore, SqliteStore};
 use clap::Parser;
 use env_logger;
+use futures::stream::{self, StreamExt};
 use log::{debug, error, info, warn};
-use md5::{Digest, Md5};
 use rusqlite::{params, Connection};
+use std::collections::HashMap;
 use std::io::Read;
-use std::path::PathBuf;
-use std::sync::{Arc, RwLock};
+use std::path::Path;
+use std::time::UNIX_EPOCH;
+use tokio::sync::{mpsc, oneshot};
 use walkdir::{DirEntry, WalkDir};
 
+/// How many files may be read/hashed/chunked concurrently. Bounds memory and
+/// open file descriptors without serializing the walk on a single lock.
+const MAX_CONCURRENT_READS: usize = 32;
+
 /// Receive a command from command-line, expecting a path from which to start walking the fs tree.
 #[derive(Parser)]
 struct Cli {
@@ -16,13 +29,33 @@ struct Cli {
     /// The path to the folder to begin walking the fs tree
     #[clap(parse(from_os_str))]
     path: Option<std::path::PathBuf>,
+    /// The checksum algorithm to use when hashing files.
+    #[clap(long, value_enum, default_value = "blake3")]
+    algorithm: Algorithm,
+    /// Destination backend for the "convert" command.
+    #[clap(long, value_enum, default_value = "jsonl")]
+    to_backend: Backend,
+    /// Older generation id to diff from. Used by the "diff" command.
+    #[clap(long)]
+    from_generation: Option<i64>,
+    /// Newer generation id to diff to. Defaults to the latest generation.
+    #[clap(long)]
+    to_generation: Option<i64>,
 }
 
 #[derive(Debug)]
 struct FileRecord {
     filename: String,
     filepath: String,
-    hash: String,
+    size: u64,
+    mtime: i64,
+    /// None for files whose size is unique in the walk, since they cannot
+    /// possibly have a duplicate and so are never hashed.
+    hash: Option<String>,
+    /// Name of the algorithm that produced `hash`, e.g. "blake3".
+    hash_algo: Option<String>,
+    /// The `walk` run that produced this record.
+    generation_id: i64,
 }
 
 #[tokio::main(flavor = "multi_thread", worker_threads = 10)]
@@ -31,23 +64,26 @@ async fn main() {
     info!("Hello, world!");
 
     let args = Cli::parse();
-    let conn = Connection::open("filesystem_dupes.db").unwrap();
-    let conn_lock = Arc::new(RwLock::new(conn));
 
     if args.command == "walk" {
         info!("Walk command received");
-        create_tables(&conn_lock);
+        let root = args.path.expect("'walk' command expects a path arg");
 
-        walk_filesystem_hashing(
-            args.path.expect("'walk' command expects a path arg"),
-            &conn_lock,
-        )
-        .await;
-        let count = match conn_lock.read().unwrap().query_row(
-            "Select count(*) from file_record;",
-            [],
-            |row| row.get(0),
-        ) {
+        let conn = Connection::open("filesystem_dupes.db").unwrap();
+        store::create_tables(&conn).unwrap();
+        let generation_id = insert_generation(&conn, &root).unwrap();
+        info!("Starting generation {}", generation_id);
+
+        let (db_tx, db_rx) = mpsc::channel(256);
+        let writer = tokio::spawn(run_db_writer(conn, db_rx));
+
+        walk_filesystem_hashing(root, args.algorithm, generation_id, db_tx).await;
+        writer.await.unwrap();
+
+        let conn = Connection::open("filesystem_dupes.db").unwrap();
+        let count = match conn.query_row("Select count(*) from file_record;", [], |row| {
+            row.get(0)
+        }) {
             Ok(count) => count,
             Err(sql_error) => {
                 error!("sql error msg: {}", sql_error);
@@ -58,51 +94,475 @@ async fn main() {
         return;
     }
     if args.command == "setup" {
-        create_tables(&conn_lock);
+        let conn = Connection::open("filesystem_dupes.db").unwrap();
+        store::create_tables(&conn).unwrap();
+    }
+    if args.command == "dupes" {
+        info!("Dupes command received");
+        let conn = Connection::open("filesystem_dupes.db").unwrap();
+        report_dupes(&conn);
     }
+    if args.command == "export" {
+        info!("Export command received");
+        let store = SqliteStore::open(Path::new("filesystem_dupes.db")).unwrap();
+        let output = args.path.expect("'export' command expects an output csv path");
+        export_csv(&store, &output).unwrap();
+    }
+    if args.command == "convert" {
+        info!("Convert command received");
+        let destination = args
+            .path
+            .expect("'convert' command expects a destination path");
+        convert_store(&destination, args.to_backend).unwrap();
+    }
+    if args.command == "diff" {
+        info!("Diff command received");
+        let conn = Connection::open("filesystem_dupes.db").unwrap();
+        let from_generation = args
+            .from_generation
+            .expect("'diff' command expects --from-generation");
+        let to_generation = match args.to_generation {
+            Some(to_generation) => to_generation,
+            None => latest_generation_id(&conn).expect("no generations recorded yet"),
+        };
+        report_generation_diff(&conn, from_generation, to_generation);
+    }
+}
+
+/// Record a new `walk` run and return its generation id.
+fn insert_generation(conn: &Connection, root: &Path) -> rusqlite::Result<i64> {
+    let started_at = std::time::SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs() as i64)
+        .unwrap_or(0);
+    conn.execute(
+        "INSERT INTO generation (root, started_at) VALUES (?1, ?2)",
+        params![root.to_string_lossy().to_string(), started_at],
+    )?;
+    Ok(conn.last_insert_rowid())
+}
+
+fn latest_generation_id(conn: &Connection) -> rusqlite::Result<i64> {
+    conn.query_row("SELECT MAX(id) FROM generation", [], |row| row.get(0))
 }
 
-fn create_tables(conn_lock: &Arc<RwLock<Connection>>) {
-    conn_lock
-        .write()
+/// A file unchanged since a previous generation, whose hash (and chunks) can
+/// be copied forward instead of re-reading the file.
+struct PreviousRecord {
+    fileno: i64,
+    hash: String,
+    hash_algo: String,
+}
+
+/// Look up the most recent record for `filepath` from a generation strictly
+/// before `generation_id`. If its size and mtime still match *and* it was
+/// hashed with `algorithm`, the caller can reuse its hash instead of
+/// re-reading the file -- a record hashed under a different algorithm can't
+/// be reused just because the file itself didn't change, or `--algorithm`
+/// would silently stop doing anything once a file's hash is cached.
+fn lookup_previous_record(
+    conn: &Connection,
+    filepath: &str,
+    size: u64,
+    mtime: i64,
+    generation_id: i64,
+    algorithm: Algorithm,
+) -> Option<PreviousRecord> {
+    conn.query_row(
+        "SELECT id, hash, hash_algo FROM file_record \
+         WHERE filepath = ?1 AND size = ?2 AND mtime = ?3 AND generation_id < ?4 \
+           AND hash IS NOT NULL AND hash_algo = ?5 \
+         ORDER BY generation_id DESC LIMIT 1",
+        params![filepath, size, mtime, generation_id, algorithm.name()],
+        |row| {
+            Ok(PreviousRecord {
+                fileno: row.get(0)?,
+                hash: row.get(1)?,
+                hash_algo: row.get(2)?,
+            })
+        },
+    )
+    .ok()
+}
+
+/// Copy every chunk row from `from_fileno` forward onto `to_fileno`, so an
+/// unchanged file's chunk breakdown doesn't need to be recomputed.
+fn copy_chunks_forward(conn: &Connection, from_fileno: i64, to_fileno: i64) -> rusqlite::Result<()> {
+    conn.execute(
+        "INSERT INTO chunk (fileno, chunk_index, chunk_hash, hash_algo, length) \
+         SELECT ?2, chunk_index, chunk_hash, hash_algo, length FROM chunk WHERE fileno = ?1",
+        params![from_fileno, to_fileno],
+    )?;
+    Ok(())
+}
+
+/// Compare two generations and print files added, removed, or changed
+/// (different size or mtime) between them.
+fn report_generation_diff(conn: &Connection, from_generation: i64, to_generation: i64) {
+    let added = collect_filepaths(
+        conn,
+        "SELECT filepath FROM file_record WHERE generation_id = ?1 \
+         AND filepath NOT IN (SELECT filepath FROM file_record WHERE generation_id = ?2)",
+        params![to_generation, from_generation],
+    );
+    let removed = collect_filepaths(
+        conn,
+        "SELECT filepath FROM file_record WHERE generation_id = ?1 \
+         AND filepath NOT IN (SELECT filepath FROM file_record WHERE generation_id = ?2)",
+        params![from_generation, to_generation],
+    );
+    let changed = collect_filepaths(
+        conn,
+        "SELECT a.filepath FROM file_record a JOIN file_record b ON a.filepath = b.filepath \
+         WHERE a.generation_id = ?1 AND b.generation_id = ?2 \
+           AND (a.size != b.size OR a.mtime != b.mtime)",
+        params![from_generation, to_generation],
+    );
+
+    println!(
+        "Diff generation {} -> {}:",
+        from_generation, to_generation
+    );
+    println!("Added ({}):", added.len());
+    for filepath in &added {
+        println!("  {}", filepath);
+    }
+    println!("Removed ({}):", removed.len());
+    for filepath in &removed {
+        println!("  {}", filepath);
+    }
+    println!("Changed ({}):", changed.len());
+    for filepath in &changed {
+        println!("  {}", filepath);
+    }
+}
+
+fn collect_filepaths(conn: &Connection, sql: &str, params: &[&dyn rusqlite::ToSql]) -> Vec<String> {
+    let mut stmt = conn.prepare(sql).unwrap();
+    stmt.query_map(params, |row| row.get(0))
         .unwrap()
-        .execute(
-            "CREATE TABLE IF NOT EXISTS file_record (
-                  id            INTEGER PRIMARY KEY,
-                  filename      TEXT NOT NULL,
-                  filepath      TEXT NOT NULL,
-                  hash          TEXT NOT NULL
-                  )",
-            [],
+        .map(|row| row.unwrap())
+        .collect()
+}
+
+/// Dump each filepath's latest-generation `file_record` row (with the
+/// duplicate-group size each belongs to) to a CSV file, so results can be
+/// consumed by other tools. Rows from older generations are not exported.
+fn export_csv(store: &dyn RecordStore, output: &Path) -> Result<(), String> {
+    let records = store.iter_all()?;
+    info!("Exporting {} records", records.len());
+    let mut writer = csv::Writer::from_path(output).map_err(|e| e.to_string())?;
+    writer
+        .write_record(["filename", "filepath", "size", "mtime", "hash", "hash_algo", "group_size"])
+        .map_err(|e| e.to_string())?;
+
+    let mut group_sizes: HashMap<&str, usize> = HashMap::new();
+    for record in &records {
+        if let Some(hash) = &record.hash {
+            *group_sizes.entry(hash.as_str()).or_insert(0) += 1;
+        }
+    }
+
+    for record in &records {
+        // A file with no hash is, by definition, in a duplicate group of one.
+        let group_size = record
+            .hash
+            .as_deref()
+            .map(|hash| group_sizes[hash])
+            .unwrap_or(1);
+        writer
+            .write_record([
+                record.filename.clone(),
+                record.filepath.clone(),
+                record.size.to_string(),
+                record.mtime.to_string(),
+                record.hash.clone().unwrap_or_default(),
+                record.hash_algo.clone().unwrap_or_default(),
+                group_size.to_string(),
+            ])
+            .map_err(|e| e.to_string())?;
+    }
+    writer.flush().map_err(|e| e.to_string())?;
+    info!("Exported records to {}", output.display());
+    Ok(())
+}
+
+/// Migrate each filepath's latest-generation record (hashed or not) from the
+/// SQLite store into a different `RecordStore` backend. Rows from older
+/// generations are not migrated.
+fn convert_store(destination: &Path, to_backend: Backend) -> Result<(), String> {
+    let source = SqliteStore::open(Path::new("filesystem_dupes.db"))?;
+    let records = source.iter_all()?;
+
+    match to_backend {
+        Backend::Sqlite => {
+            let mut dest = SqliteStore::open(destination)?;
+            for record in records {
+                dest.insert_record(record)?;
+            }
+            dest.flush()?;
+        }
+        Backend::Jsonl => {
+            let mut dest = JsonlStore::open(destination)?;
+            for record in records {
+                dest.insert_record(record)?;
+            }
+            dest.flush()?;
+        }
+    }
+    info!("Converted records into {}", destination.display());
+    Ok(())
+}
+
+/// A group of files sharing the same hash, along with the size of each copy.
+struct DupeGroup {
+    hash: String,
+    size: u64,
+    filepaths: Vec<String>,
+}
+
+/// Query the `file_record` table for hash collisions and print each duplicate
+/// cluster, followed by a summary of redundant copies and reclaimable bytes.
+///
+/// Only the latest generation's row for each filepath is considered, since
+/// an unchanged file re-scanned across generations copies its hash forward
+/// into a new row and would otherwise collide with itself.
+fn report_dupes(conn: &Connection) {
+    let mut stmt = conn
+        .prepare(
+            "SELECT hash, size, COUNT(*), GROUP_CONCAT(filepath) FROM file_record \
+             WHERE hash IS NOT NULL \
+               AND generation_id = (SELECT MAX(generation_id) FROM file_record newer \
+                                    WHERE newer.filepath = file_record.filepath) \
+             GROUP BY hash HAVING COUNT(*) > 1",
         )
         .unwrap();
+    let groups = stmt
+        .query_map([], |row| {
+            let hash: String = row.get(0)?;
+            let size: i64 = row.get(1)?;
+            let concat: String = row.get(3)?;
+            Ok(DupeGroup {
+                hash,
+                size: size as u64,
+                filepaths: concat.split(',').map(str::to_string).collect(),
+            })
+        })
+        .unwrap();
+
+    let mut total_duplicate_files = 0usize;
+    let mut redundant_copies = 0usize;
+    let mut reclaimable_bytes: u64 = 0;
+
+    for group in groups {
+        let group = group.unwrap();
+        println!("Duplicate group (hash {}):", group.hash);
+        for filepath in &group.filepaths {
+            println!("  {}", filepath);
+        }
+
+        total_duplicate_files += group.filepaths.len();
+        redundant_copies += group.filepaths.len() - 1;
+        reclaimable_bytes += group.size * (group.filepaths.len() as u64 - 1);
+    }
+
+    println!("Summary:");
+    println!("  Duplicate files: {}", total_duplicate_files);
+    println!("  Redundant copies: {}", redundant_copies);
+    println!("  Reclaimable bytes: {}", reclaimable_bytes);
+
+    report_chunk_overlap(conn);
 }
 
-async fn insert_file_record(
-    conn_lock: &Arc<RwLock<Connection>>,
-    record: FileRecord,
-) -> Result<(), &str> {
-    return match conn_lock.write().unwrap().execute(
-        "INSERT INTO file_record (filename, filepath, hash) VALUES (?1, ?2, ?3)",
-        params![record.filename, record.filepath, record.hash],
-    ) {
-        Ok(_) => {
-            debug!("{} inserted into file_record table", record.filename);
-            Ok(())
+/// Report every content-defined chunk shared by more than one file, so
+/// partial overlap (e.g. VM images, appended-to logs) shows up alongside
+/// exact matches. Whole-file duplicates share all their chunks and are
+/// listed here as well; they are not filtered out.
+///
+/// As in `report_dupes`, restricted to each filepath's latest generation so
+/// a copied-forward row doesn't overlap with its own earlier generation.
+fn report_chunk_overlap(conn: &Connection) {
+    let mut stmt = conn
+        .prepare(
+            "SELECT chunk_hash, hash_algo, GROUP_CONCAT(DISTINCT file_record.filepath) \
+             FROM chunk JOIN file_record ON file_record.id = chunk.fileno \
+             WHERE file_record.generation_id = (SELECT MAX(generation_id) FROM file_record newer \
+                                                WHERE newer.filepath = file_record.filepath) \
+             GROUP BY chunk_hash, hash_algo HAVING COUNT(DISTINCT chunk.fileno) > 1",
+        )
+        .unwrap();
+    let rows = stmt
+        .query_map([], |row| {
+            let chunk_hash: String = row.get(0)?;
+            let hash_algo: String = row.get(1)?;
+            let concat: String = row.get(2)?;
+            Ok((
+                chunk_hash,
+                hash_algo,
+                concat
+                    .split(',')
+                    .map(str::to_string)
+                    .collect::<Vec<String>>(),
+            ))
+        })
+        .unwrap();
+
+    let mut shared_chunks = 0usize;
+    for row in rows {
+        let (chunk_hash, hash_algo, filepaths) = row.unwrap();
+        if filepaths.len() < 2 {
+            continue;
         }
-        Err(err) => {
-            let err_msg = format!("Error inserting file_record: {err}");
-            warn!("{}", err_msg);
-            Err("Failed to insert into db")
+        shared_chunks += 1;
+        println!("Shared chunk ({} hash {}):", hash_algo, chunk_hash);
+        for filepath in &filepaths {
+            println!("  {}", filepath);
         }
-    };
+    }
+    println!("  Chunks shared across distinct files: {}", shared_chunks);
+}
+
+fn insert_file_record(conn: &Connection, record: &FileRecord) -> rusqlite::Result<i64> {
+    conn.execute(
+        "INSERT INTO file_record (filename, filepath, size, mtime, hash, hash_algo, generation_id) \
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+        params![
+            record.filename,
+            record.filepath,
+            record.size,
+            record.mtime,
+            record.hash,
+            record.hash_algo,
+            record.generation_id
+        ],
+    )?;
+    debug!("{} inserted into file_record table", record.filename);
+    Ok(conn.last_insert_rowid())
+}
+
+fn insert_chunk(conn: &Connection, fileno: i64, chunk: &Chunk) -> rusqlite::Result<()> {
+    conn.execute(
+        "INSERT INTO chunk (fileno, chunk_index, chunk_hash, hash_algo, length) \
+         VALUES (?1, ?2, ?3, ?4, ?5)",
+        params![
+            fileno,
+            chunk.index,
+            chunk.hash,
+            chunk.hash_algo,
+            chunk.length
+        ],
+    )?;
+    Ok(())
+}
+
+/// A message sent by walk workers to the single DB-writer task. This is the
+/// only path by which worker tasks touch the database, so there is never
+/// lock contention on the connection.
+enum DbMessage {
+    LookupPrevious {
+        filepath: String,
+        size: u64,
+        mtime: i64,
+        generation_id: i64,
+        algorithm: Algorithm,
+        reply: oneshot::Sender<Option<PreviousRecord>>,
+    },
+    InsertFile {
+        record: FileRecord,
+        reply: oneshot::Sender<i64>,
+    },
+    InsertChunks {
+        fileno: i64,
+        chunks: Vec<Chunk>,
+    },
+    CopyChunks {
+        from_fileno: i64,
+        to_fileno: i64,
+    },
 }
 
-async fn walk_filesystem_hashing(root: std::path::PathBuf, conn_lock: &Arc<RwLock<Connection>>) {
+/// Owns the single writable `Connection` and applies every `DbMessage` sent
+/// by worker tasks. Drains as many messages as are already queued into one
+/// transaction before committing, so a busy walk batches its inserts instead
+/// of committing one row at a time.
+async fn run_db_writer(mut conn: Connection, mut rx: mpsc::Receiver<DbMessage>) {
+    while let Some(first) = rx.recv().await {
+        let mut batch = vec![first];
+        while let Ok(msg) = rx.try_recv() {
+            batch.push(msg);
+        }
+
+        let tx = conn.transaction().unwrap();
+        for msg in batch {
+            match msg {
+                DbMessage::LookupPrevious {
+                    filepath,
+                    size,
+                    mtime,
+                    generation_id,
+                    algorithm,
+                    reply,
+                } => {
+                    let previous = lookup_previous_record(
+                        &tx,
+                        &filepath,
+                        size,
+                        mtime,
+                        generation_id,
+                        algorithm,
+                    );
+                    let _ = reply.send(previous);
+                }
+                DbMessage::InsertFile { record, reply } => {
+                    let fileno = insert_file_record(&tx, &record).unwrap();
+                    let _ = reply.send(fileno);
+                }
+                DbMessage::InsertChunks { fileno, chunks } => {
+                    for chunk in &chunks {
+                        insert_chunk(&tx, fileno, chunk).unwrap();
+                    }
+                }
+                DbMessage::CopyChunks {
+                    from_fileno,
+                    to_fileno,
+                } => {
+                    copy_chunks_forward(&tx, from_fileno, to_fileno).unwrap();
+                }
+            }
+        }
+        tx.commit().unwrap();
+    }
+}
+
+/// Count how many walked files share each size. A size with a count of 1 is
+/// unique in the walk and so cannot possibly have a byte-identical
+/// duplicate, letting the caller skip hashing it entirely.
+fn count_by_size(sizes: impl IntoIterator<Item = u64>) -> HashMap<u64, usize> {
+    let mut counts = HashMap::new();
+    for size in sizes {
+        *counts.entry(size).or_insert(0) += 1;
+    }
+    counts
+}
+
+/// A walked file along with the metadata collected during the enumeration pass.
+struct WalkedFile {
+    entry: DirEntry,
+    size: u64,
+    mtime: i64,
+}
+
+async fn walk_filesystem_hashing(
+    root: std::path::PathBuf,
+    algorithm: Algorithm,
+    generation_id: i64,
+    db_tx: mpsc::Sender<DbMessage>,
+) {
     info!("Walking {}", root.display());
     let files = WalkDir::new(root).same_file_system(true);
 
-    let mut handles = vec![];
+    // First pass: enumerate every entry, recording size and mtime only.
+    let mut walked = vec![];
     for file_result in files {
         let file = match file_result {
             Ok(file) => {
@@ -121,30 +581,144 @@ async fn walk_filesystem_hashing(root: std::path::PathBuf, conn_lock: &Arc<RwLoc
                 continue;
             }
         };
-        handles.push(digest_and_insert_path(file, &conn_lock));
+        if file.path().is_dir() {
+            debug!("Directory found: {}", file.path().to_string_lossy());
+            continue;
+        }
+        let metadata = match file.metadata() {
+            Ok(metadata) => metadata,
+            Err(e) => {
+                debug!("Could not read metadata for {:?}: {}", file.path(), e);
+                continue;
+            }
+        };
+        let mtime = metadata
+            .modified()
+            .ok()
+            .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+            .map(|duration| duration.as_secs() as i64)
+            .unwrap_or(0);
+        walked.push(WalkedFile {
+            entry: file,
+            size: metadata.len(),
+            mtime,
+        });
     }
-    info!("Joining {} async handles", handles.len());
-    futures::future::join_all(handles).await;
+
+    // Only files whose size collides with at least one other file can
+    // possibly be byte-identical, so only those need to be hashed.
+    let size_counts = count_by_size(walked.iter().map(|walked_file| walked_file.size));
+
+    let total = walked.len();
+    stream::iter(walked)
+        .for_each_concurrent(MAX_CONCURRENT_READS, |walked_file| {
+            let needs_hash = size_counts[&walked_file.size] > 1;
+            let db_tx = db_tx.clone();
+            digest_and_insert_path(walked_file, needs_hash, algorithm, generation_id, db_tx)
+        })
+        .await;
+    info!("Walked {} files", total);
 }
 
-async fn digest_and_insert_path(file: DirEntry, conn_lock: &Arc<RwLock<Connection>>) {
-    debug!("digest: {:?}", file);
-    let path = file.into_path();
-    if path.is_dir() {
-        debug!("Directory found: {}", path.to_str().unwrap());
-        return;
-    }
-    let digest = calculate_digest(&path).await.unwrap();
+async fn digest_and_insert_path(
+    walked_file: WalkedFile,
+    needs_hash: bool,
+    algorithm: Algorithm,
+    generation_id: i64,
+    db_tx: mpsc::Sender<DbMessage>,
+) {
+    debug!("digest: {:?}", walked_file.entry);
+    let path = walked_file.entry.into_path();
+    let filepath = path.to_string_lossy().to_string();
+
+    let (lookup_tx, lookup_rx) = oneshot::channel();
+    let previous = if db_tx
+        .send(DbMessage::LookupPrevious {
+            filepath: filepath.clone(),
+            size: walked_file.size,
+            mtime: walked_file.mtime,
+            generation_id,
+            algorithm,
+            reply: lookup_tx,
+        })
+        .await
+        .is_ok()
+    {
+        lookup_rx.await.ok().flatten()
+    } else {
+        None
+    };
+
+    let (hash, hash_algo) = if let Some(previous) = &previous {
+        debug!(
+            "{} unchanged since a previous generation, reusing hash",
+            filepath
+        );
+        (Some(previous.hash.clone()), Some(previous.hash_algo.clone()))
+    } else if needs_hash {
+        let digest_path = path.clone();
+        let hash = tokio::task::spawn_blocking(move || calculate_digest(&digest_path, algorithm))
+            .await
+            .unwrap();
+        let hash_algo = hash.as_ref().map(|_| algorithm.name().to_string());
+        (hash, hash_algo)
+    } else {
+        (None, None)
+    };
+
     let record = FileRecord {
         filename: path.file_name().unwrap().to_string_lossy().to_string(),
-        filepath: path.to_string_lossy().to_string(),
-        hash: digest,
+        filepath,
+        size: walked_file.size,
+        mtime: walked_file.mtime,
+        hash,
+        hash_algo,
+        generation_id,
+    };
+
+    let (reply_tx, reply_rx) = oneshot::channel();
+    if db_tx
+        .send(DbMessage::InsertFile {
+            record,
+            reply: reply_tx,
+        })
+        .await
+        .is_err()
+    {
+        warn!("DB writer task is gone, dropping record for {:?}", path);
+        return;
+    }
+    let fileno = match reply_rx.await {
+        Ok(fileno) => fileno,
+        Err(_) => return,
     };
-    insert_file_record(&conn_lock, record).await.unwrap()
+
+    if let Some(previous) = previous {
+        let _ = db_tx
+            .send(DbMessage::CopyChunks {
+                from_fileno: previous.fileno,
+                to_fileno: fileno,
+            })
+            .await;
+        return;
+    }
+
+    let chunk_path = path.clone();
+    let chunks = tokio::task::spawn_blocking(move || {
+        chunker::chunk_file_at_path(&chunk_path, algorithm)
+    })
+    .await
+    .unwrap();
+    match chunks {
+        Ok(chunks) => {
+            let _ = db_tx.send(DbMessage::InsertChunks { fileno, chunks }).await;
+        }
+        Err(e) => warn!("Failed to chunk {}: {}", path.display(), e),
+    }
 }
 
-async fn calculate_digest(file: &PathBuf) -> Option<String> {
-    debug!("Calculate digest: {:?}", file);
+fn calculate_digest(file: &Path, algorithm: Algorithm) -> Option<String> {
+    debug!("Calculate digest ({:?}): {:?}", algorithm, file);
     let mut f = match std::fs::File::open(file) {
         Ok(f) => f,
         Err(open_error) => {
@@ -157,9 +731,9 @@ async fn calculate_digest(file: &PathBuf) -> Option<String> {
         }
     };
 
-    let mut md5 = Md5::new();
+    let mut checksummer = algorithm.checksummer();
     let chunk_size = 0x4000;
-    let md5_result = loop {
+    loop {
         let mut chunk = Vec::with_capacity(chunk_size);
         match f
             .by_ref()
@@ -169,15 +743,87 @@ async fn calculate_digest(file: &PathBuf) -> Option<String> {
         {
             Some(n) => {
                 if n == 0 {
-                    break md5.finalize();
+                    break;
                 }
-                md5.update(chunk);
+                checksummer.update(&chunk);
                 if n < chunk_size {
-                    break md5.finalize();
+                    break;
                 }
             }
-            None => break md5.finalize(),
+            None => break,
         }
-    };
-    Some(format!("{:x}", md5_result))
+    }
+    Some(checksummer.finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn count_by_size_counts_collisions() {
+        let counts = count_by_size([10, 10, 20, 30, 30, 30]);
+        assert_eq!(counts[&10], 2);
+        assert_eq!(counts[&20], 1);
+        assert_eq!(counts[&30], 3);
+    }
+
+    #[test]
+    fn size_unique_files_are_not_flagged_for_hashing() {
+        let counts = count_by_size([5, 10, 10]);
+        let needs_hash = |size: u64| counts[&size] > 1;
+        assert!(!needs_hash(5));
+        assert!(needs_hash(10));
+    }
+
+    #[test]
+    fn lookup_previous_record_reuses_a_hash_from_the_same_algorithm() {
+        let conn = Connection::open(":memory:").unwrap();
+        store::create_tables(&conn).unwrap();
+        let gen1 = insert_generation(&conn, Path::new("/data")).unwrap();
+        insert_file_record(
+            &conn,
+            &FileRecord {
+                filename: "a".into(),
+                filepath: "/data/a".into(),
+                size: 10,
+                mtime: 100,
+                hash: Some("md5hash".into()),
+                hash_algo: Some("md5".into()),
+                generation_id: gen1,
+            },
+        )
+        .unwrap();
+        let gen2 = insert_generation(&conn, Path::new("/data")).unwrap();
+
+        let reused = lookup_previous_record(&conn, "/data/a", 10, 100, gen2, Algorithm::Md5);
+        assert!(reused.is_some());
+    }
+
+    #[test]
+    fn lookup_previous_record_ignores_a_hash_from_a_different_algorithm() {
+        let conn = Connection::open(":memory:").unwrap();
+        store::create_tables(&conn).unwrap();
+        let gen1 = insert_generation(&conn, Path::new("/data")).unwrap();
+        insert_file_record(
+            &conn,
+            &FileRecord {
+                filename: "a".into(),
+                filepath: "/data/a".into(),
+                size: 10,
+                mtime: 100,
+                hash: Some("md5hash".into()),
+                hash_algo: Some("md5".into()),
+                generation_id: gen1,
+            },
+        )
+        .unwrap();
+        let gen2 = insert_generation(&conn, Path::new("/data")).unwrap();
+
+        // The file is byte-for-byte unchanged, but a re-scan with a
+        // different --algorithm must not reuse the md5 hash as if it were
+        // a blake3 hash.
+        let reused = lookup_previous_record(&conn, "/data/a", 10, 100, gen2, Algorithm::Blake3);
+        assert!(reused.is_none());
+    }
 }
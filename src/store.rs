@@ -0,0 +1,358 @@
+//! Storage backend abstraction.
+//!
+//! SQLite access used to be hardcoded throughout `main`. `RecordStore` gives
+//! the `export`/`convert` commands a narrow interface -- insert a record,
+//! count records, iterate grouped by hash -- so a result set can be read
+//! from one backend and written to another without either command needing
+//! to know which is which.
+
+use rusqlite::{params, Connection};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+
+/// One `file_record` row, backend-agnostic.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StoredRecord {
+    pub filename: String,
+    pub filepath: String,
+    pub size: u64,
+    pub mtime: i64,
+    pub hash: Option<String>,
+    pub hash_algo: Option<String>,
+    /// The `walk` run that produced this record.
+    pub generation_id: i64,
+}
+
+/// Which `RecordStore` implementation a `convert` should write into.
+#[derive(Clone, Copy, Debug, clap::ValueEnum)]
+pub enum Backend {
+    Sqlite,
+    Jsonl,
+}
+
+pub trait RecordStore {
+    fn insert_record(&mut self, record: StoredRecord) -> Result<(), String>;
+    fn count(&self) -> Result<usize, String>;
+    /// Every record at its filepath's latest generation, hashed or not. Use
+    /// this for a full dump (`export`, `convert`) -- `iter_by_hash` drops
+    /// any record without a hash, which is most files once chunk0-2's
+    /// size-collision gating is in effect.
+    fn iter_all(&self) -> Result<Vec<StoredRecord>, String>;
+    /// The latest-generation record for each hash, grouped by that hash.
+    /// Records from a filepath's older generations are excluded, so a file
+    /// that simply got re-scanned unchanged doesn't show up as its own
+    /// duplicate. Reserved for dupe reporting, where an unhashed file (by
+    /// definition not a duplicate of anything) has nothing to contribute.
+    fn iter_by_hash(&self) -> Result<BTreeMap<String, Vec<StoredRecord>>, String>;
+    /// Flush any writes buffered in memory. No-op for backends that persist
+    /// every `insert_record` immediately.
+    fn flush(&mut self) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+/// Create the `generation`/`file_record`/`chunk` tables if they don't already
+/// exist. The single source of truth for the schema, called by `setup`,
+/// `walk` and `SqliteStore::open` so `walk` and `export`/`convert` never
+/// disagree about what `file_record` looks like.
+pub fn create_tables(conn: &Connection) -> rusqlite::Result<()> {
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS generation (
+              id            INTEGER PRIMARY KEY,
+              root          TEXT NOT NULL,
+              started_at    INTEGER NOT NULL
+              )",
+        [],
+    )?;
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS file_record (
+              id            INTEGER PRIMARY KEY,
+              filename      TEXT NOT NULL,
+              filepath      TEXT NOT NULL,
+              size          INTEGER NOT NULL,
+              mtime         INTEGER NOT NULL,
+              hash          TEXT,
+              hash_algo     TEXT,
+              generation_id INTEGER NOT NULL
+              )",
+        [],
+    )?;
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS chunk (
+              id            INTEGER PRIMARY KEY,
+              fileno        INTEGER NOT NULL,
+              chunk_index   INTEGER NOT NULL,
+              chunk_hash    TEXT NOT NULL,
+              hash_algo     TEXT NOT NULL,
+              length        INTEGER NOT NULL
+              )",
+        [],
+    )?;
+    Ok(())
+}
+
+/// The original SQLite-backed store.
+pub struct SqliteStore {
+    conn: Connection,
+}
+
+impl SqliteStore {
+    pub fn open(path: &Path) -> Result<Self, String> {
+        let conn = Connection::open(path).map_err(|e| e.to_string())?;
+        create_tables(&conn).map_err(|e| e.to_string())?;
+        Ok(SqliteStore { conn })
+    }
+}
+
+impl RecordStore for SqliteStore {
+    fn insert_record(&mut self, record: StoredRecord) -> Result<(), String> {
+        self.conn
+            .execute(
+                "INSERT INTO file_record (filename, filepath, size, mtime, hash, hash_algo, generation_id) \
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+                params![
+                    record.filename,
+                    record.filepath,
+                    record.size,
+                    record.mtime,
+                    record.hash,
+                    record.hash_algo,
+                    record.generation_id
+                ],
+            )
+            .map_err(|e| e.to_string())?;
+        Ok(())
+    }
+
+    fn count(&self) -> Result<usize, String> {
+        self.conn
+            .query_row("SELECT COUNT(*) FROM file_record", [], |row| row.get(0))
+            .map_err(|e| e.to_string())
+    }
+
+    fn iter_all(&self) -> Result<Vec<StoredRecord>, String> {
+        let mut stmt = self
+            .conn
+            .prepare(
+                "SELECT filename, filepath, size, mtime, hash, hash_algo, generation_id FROM file_record \
+                 WHERE generation_id = (SELECT MAX(generation_id) FROM file_record newer \
+                                        WHERE newer.filepath = file_record.filepath)",
+            )
+            .map_err(|e| e.to_string())?;
+        let rows = stmt
+            .query_map([], Self::row_to_record)
+            .map_err(|e| e.to_string())?;
+        rows.map(|row| row.map_err(|e| e.to_string())).collect()
+    }
+
+    fn iter_by_hash(&self) -> Result<BTreeMap<String, Vec<StoredRecord>>, String> {
+        let mut stmt = self
+            .conn
+            .prepare(
+                "SELECT filename, filepath, size, mtime, hash, hash_algo, generation_id FROM file_record \
+                 WHERE hash IS NOT NULL \
+                   AND generation_id = (SELECT MAX(generation_id) FROM file_record newer \
+                                        WHERE newer.filepath = file_record.filepath)",
+            )
+            .map_err(|e| e.to_string())?;
+        let rows = stmt
+            .query_map([], Self::row_to_record)
+            .map_err(|e| e.to_string())?;
+
+        let mut grouped: BTreeMap<String, Vec<StoredRecord>> = BTreeMap::new();
+        for row in rows {
+            let record = row.map_err(|e| e.to_string())?;
+            if let Some(hash) = record.hash.clone() {
+                grouped.entry(hash).or_default().push(record);
+            }
+        }
+        Ok(grouped)
+    }
+}
+
+impl SqliteStore {
+    fn row_to_record(row: &rusqlite::Row) -> rusqlite::Result<StoredRecord> {
+        Ok(StoredRecord {
+            filename: row.get(0)?,
+            filepath: row.get(1)?,
+            size: row.get::<_, i64>(2)? as u64,
+            mtime: row.get(3)?,
+            hash: row.get(4)?,
+            hash_algo: row.get(5)?,
+            generation_id: row.get(6)?,
+        })
+    }
+}
+
+/// A lightweight JSONL-backed store: one `StoredRecord` per line, loaded
+/// fully into memory on open. Fine for the scale this tool targets.
+pub struct JsonlStore {
+    path: PathBuf,
+    records: Vec<StoredRecord>,
+}
+
+impl JsonlStore {
+    pub fn open(path: &Path) -> Result<Self, String> {
+        let mut records = vec![];
+        if path.exists() {
+            let file = File::open(path).map_err(|e| e.to_string())?;
+            for line in BufReader::new(file).lines() {
+                let line = line.map_err(|e| e.to_string())?;
+                if line.trim().is_empty() {
+                    continue;
+                }
+                records.push(serde_json::from_str(&line).map_err(|e| e.to_string())?);
+            }
+        }
+        Ok(JsonlStore {
+            path: path.to_path_buf(),
+            records,
+        })
+    }
+
+    /// Rewrite the whole file from the in-memory records. Called explicitly
+    /// via `flush` rather than after every `insert_record`, so a bulk load
+    /// (e.g. `convert`) doesn't rewrite the file once per record.
+    fn persist(&self) -> Result<(), String> {
+        let mut file = File::create(&self.path).map_err(|e| e.to_string())?;
+        for record in &self.records {
+            let line = serde_json::to_string(record).map_err(|e| e.to_string())?;
+            writeln!(file, "{}", line).map_err(|e| e.to_string())?;
+        }
+        Ok(())
+    }
+
+    /// The most recent generation each filepath appears in.
+    fn latest_generations(&self) -> BTreeMap<&str, i64> {
+        let mut latest: BTreeMap<&str, i64> = BTreeMap::new();
+        for record in &self.records {
+            let entry = latest.entry(record.filepath.as_str()).or_insert(record.generation_id);
+            if record.generation_id > *entry {
+                *entry = record.generation_id;
+            }
+        }
+        latest
+    }
+}
+
+impl RecordStore for JsonlStore {
+    fn insert_record(&mut self, record: StoredRecord) -> Result<(), String> {
+        self.records.push(record);
+        Ok(())
+    }
+
+    fn count(&self) -> Result<usize, String> {
+        Ok(self.records.len())
+    }
+
+    fn iter_all(&self) -> Result<Vec<StoredRecord>, String> {
+        let latest = self.latest_generations();
+        Ok(self
+            .records
+            .iter()
+            .filter(|record| latest.get(record.filepath.as_str()) == Some(&record.generation_id))
+            .cloned()
+            .collect())
+    }
+
+    fn iter_by_hash(&self) -> Result<BTreeMap<String, Vec<StoredRecord>>, String> {
+        let latest = self.latest_generations();
+        let mut grouped: BTreeMap<String, Vec<StoredRecord>> = BTreeMap::new();
+        for record in &self.records {
+            if latest.get(record.filepath.as_str()) != Some(&record.generation_id) {
+                continue;
+            }
+            if let Some(hash) = record.hash.clone() {
+                grouped.entry(hash).or_default().push(record.clone());
+            }
+        }
+        Ok(grouped)
+    }
+
+    fn flush(&mut self) -> Result<(), String> {
+        self.persist()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(filepath: &str, hash: Option<&str>, generation_id: i64) -> StoredRecord {
+        StoredRecord {
+            filename: filepath.to_string(),
+            filepath: filepath.to_string(),
+            size: 10,
+            mtime: 0,
+            hash: hash.map(str::to_string),
+            hash_algo: hash.map(|_| "blake3".to_string()),
+            generation_id,
+        }
+    }
+
+    #[test]
+    fn sqlite_store_iter_all_includes_unhashed_records() {
+        let mut store = SqliteStore::open(Path::new(":memory:")).unwrap();
+        store.insert_record(record("/a", Some("deadbeef"), 1)).unwrap();
+        store.insert_record(record("/b", None, 1)).unwrap();
+
+        assert_eq!(store.count().unwrap(), 2);
+        assert_eq!(store.iter_all().unwrap().len(), 2);
+        assert_eq!(store.iter_by_hash().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn sqlite_store_ignores_older_generations_of_the_same_file() {
+        let mut store = SqliteStore::open(Path::new(":memory:")).unwrap();
+        store.insert_record(record("/a", Some("deadbeef"), 1)).unwrap();
+        store.insert_record(record("/a", Some("deadbeef"), 2)).unwrap();
+
+        let grouped = store.iter_by_hash().unwrap();
+        assert_eq!(grouped.get("deadbeef").map(Vec::len), Some(1));
+        assert_eq!(store.iter_all().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn jsonl_store_round_trips_through_a_file() {
+        let path = std::env::temp_dir().join(format!(
+            "filesystem-dupe-scraper-test-{}-{}.jsonl",
+            std::process::id(),
+            "round-trip"
+        ));
+        let mut store = JsonlStore::open(&path).unwrap();
+        store.insert_record(record("/a", Some("deadbeef"), 1)).unwrap();
+        store.insert_record(record("/b", None, 1)).unwrap();
+        store.flush().unwrap();
+
+        let reopened = JsonlStore::open(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(reopened.count().unwrap(), 2);
+        assert_eq!(reopened.iter_all().unwrap().len(), 2);
+        assert_eq!(
+            reopened.iter_by_hash().unwrap().get("deadbeef").map(Vec::len),
+            Some(1)
+        );
+    }
+
+    #[test]
+    fn jsonl_store_ignores_older_generations_of_the_same_file() {
+        let path = std::env::temp_dir().join(format!(
+            "filesystem-dupe-scraper-test-{}-{}.jsonl",
+            std::process::id(),
+            "generations"
+        ));
+        let mut store = JsonlStore::open(&path).unwrap();
+        store.insert_record(record("/a", Some("deadbeef"), 1)).unwrap();
+        store.insert_record(record("/a", Some("deadbeef"), 2)).unwrap();
+
+        assert_eq!(store.iter_all().unwrap().len(), 1);
+        assert_eq!(
+            store.iter_by_hash().unwrap().get("deadbeef").map(Vec::len),
+            Some(1)
+        );
+    }
+}